@@ -0,0 +1,100 @@
+// Lets an older client that only speaks `VibrateCmd`/`SingleMotorVibrateCmd`
+// (pre-spec-2) control a device whose attributes are authored in the newer
+// `ScalarCmd` form, the way protocols like `Lovense` populate them. Two
+// things have to happen, and only one of them is done:
+//
+// - Commands coming *in* from the client get expanded from the legacy
+//   single-value shape into the per-motor `VibrateCmd` the current
+//   `GenericCommandManager::update_vibration` expects. The current-spec
+//   `VibrateCmd` is already in that per-motor shape, so it passes straight
+//   through unchanged -- only `SingleMotorVibrateCmd` needs expanding. This
+//   half IS wired in, to the Lovense protocol's command dispatch in
+//   `device::protocol::lovense`.
+// - Device attributes going *out* to the client would need a legacy
+//   `vibrate_cmd` descriptor derived from the scalar feature list (with
+//   zero-motor descriptors dropped, which is what
+//   `downgrade_vibrate_attributes` below computes). This half is NOT wired
+//   to anything -- there is no device-attribute-serialization path in this
+//   tree yet for it to hook into. Until that path exists,
+//   `downgrade_vibrate_attributes` is dead code outside its own unit tests
+//   below; treat wiring it in as a separate follow-up request, not as part
+//   of what this one delivers.
+use crate::core::messages::{SingleMotorVibrateCmd, VibrateCmd, VibrateSubcommand};
+
+// A single actuator entry out of a device's newer-spec `ScalarCmd`
+// attribute list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScalarFeature {
+    pub actuator_type: String,
+}
+
+// The legacy `vibrate_cmd` descriptor a pre-spec-2 client expects to see in
+// a device's attributes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VibrateCmdAttributes {
+    pub feature_count: u32,
+}
+
+// Derives the legacy `vibrate_cmd` descriptor from a device's scalar feature
+// list, or `None` if the device has no vibrating actuators to downgrade --
+// an older client must never see a zero-motor `VibrateCmd` descriptor, since
+// it will refuse to use it.
+//
+// Not yet called from anywhere outside its own tests -- see the module doc
+// comment above.
+pub fn downgrade_vibrate_attributes(scalar_features: &[ScalarFeature]) -> Option<VibrateCmdAttributes> {
+    let feature_count = scalar_features
+        .iter()
+        .filter(|f| f.actuator_type == "Vibrate")
+        .count() as u32;
+    if feature_count == 0 {
+        None
+    } else {
+        Some(VibrateCmdAttributes { feature_count })
+    }
+}
+
+// Expands a legacy `SingleMotorVibrateCmd` into the per-motor `VibrateCmd`
+// the generic command manager expects, fanning the single speed out across
+// every motor the device exposes.
+pub fn expand_single_motor_vibrate_cmd(msg: SingleMotorVibrateCmd, motor_count: u32) -> VibrateCmd {
+    let subcommands = (0..motor_count)
+        .map(|i| VibrateSubcommand::new(i, msg.speed))
+        .collect();
+    VibrateCmd::new(msg.device_index, subcommands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downgrade_single_vibrate_feature() {
+        let scalar_features = vec![ScalarFeature { actuator_type: "Vibrate".to_owned() }];
+        let attrs = downgrade_vibrate_attributes(&scalar_features).expect("one vibrate feature should downgrade");
+        assert_eq!(attrs.feature_count, 1);
+    }
+
+    #[test]
+    fn test_downgrade_prunes_device_with_no_vibrate_features() {
+        let scalar_features = vec![ScalarFeature { actuator_type: "Oscillate".to_owned() }];
+        assert!(downgrade_vibrate_attributes(&scalar_features).is_none());
+    }
+
+    #[test]
+    fn test_expand_single_motor_vibrate_cmd_round_trip() {
+        let legacy = SingleMotorVibrateCmd::new(0, 0.5);
+        let expanded = expand_single_motor_vibrate_cmd(legacy, 1);
+        assert_eq!(expanded.speeds.len(), 1);
+        assert_eq!(expanded.speeds[0].index, 0);
+        assert_eq!(expanded.speeds[0].speed, 0.5);
+    }
+
+    #[test]
+    fn test_expand_single_motor_vibrate_cmd_fans_out_to_every_motor() {
+        let legacy = SingleMotorVibrateCmd::new(0, 0.3);
+        let expanded = expand_single_motor_vibrate_cmd(legacy, 3);
+        assert_eq!(expanded.speeds.len(), 3);
+        assert!(expanded.speeds.iter().all(|s| s.speed == 0.3));
+    }
+}
@@ -0,0 +1,211 @@
+// Tracks the last command sent to each actor on a device (one vibration
+// speed per motor, one rotation speed/direction pair per motor), so protocol
+// command handlers can diff an incoming command against what the device was
+// last told rather than keeping that bookkeeping themselves.
+use crate::core::{
+    errors::ButtplugError,
+    messages::{RotateCmd, VibrateCmd},
+};
+
+// What changed (if anything) about a single motor's rotation command,
+// relative to the last rotation command sent to that motor. Lovense splits
+// rotation speed and direction across two separate firmware commands
+// ("Rotate:<speed>;" and "RotateChange;"), so a protocol needs to know which
+// of the two actually moved instead of just the new target values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RotationSubcommandDelta {
+    pub speed: u32,
+    pub clockwise: bool,
+    pub speed_changed: bool,
+    pub clockwise_changed: bool,
+}
+
+// What changed (if anything) about a single motor's vibration command,
+// relative to the last vibration command sent to that motor. Mirrors
+// `RotationSubcommandDelta`, except a motor we've never heard from before
+// counts as changed rather than unchanged -- unlike rotation, Lovense
+// vibrate commands don't need to suppress a first write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VibrationSubcommandDelta {
+    pub speed: u32,
+    pub speed_changed: bool,
+}
+
+pub struct GenericCommandManager {
+    last_vibration: Vec<Option<u32>>,
+    last_rotation: Vec<Option<(u32, bool)>>,
+}
+
+impl GenericCommandManager {
+    pub fn new() -> Self {
+        Self {
+            last_vibration: vec![],
+            last_rotation: vec![],
+        }
+    }
+
+    // How many vibrating motors we currently know this device has, based on
+    // the widest `VibrateCmd` seen so far. Falls back to 1 if we haven't
+    // seen a `VibrateCmd` yet, since every vibrating device has at least one
+    // motor -- used to fan a single-value legacy command out across motors
+    // before we've learned the real count.
+    pub fn vibration_motor_count(&self) -> u32 {
+        self.last_vibration.len().max(1) as u32
+    }
+
+    pub fn update_vibration(
+        &mut self,
+        msg: VibrateCmd,
+    ) -> Result<Vec<Option<VibrationSubcommandDelta>>, ButtplugError> {
+        if self.last_vibration.len() < msg.speeds.len() {
+            self.last_vibration.resize(msg.speeds.len(), None);
+        }
+        let mut result = vec![None; self.last_vibration.len()];
+        for speed_cmd in msg.speeds {
+            let index = speed_cmd.index as usize;
+            let speed = speed_cmd.speed;
+            let speed_changed = match self.last_vibration[index] {
+                Some(last_speed) => last_speed != speed,
+                None => true,
+            };
+            result[index] = Some(VibrationSubcommandDelta { speed, speed_changed });
+            self.last_vibration[index] = Some(speed);
+        }
+        Ok(result)
+    }
+
+    // Returns, per motor, both the requested rotation and whether its speed
+    // and/or direction actually differ from the last rotation command sent
+    // to that motor, so a caller can decide which of a split speed/direction
+    // firmware command pair it still needs to send.
+    pub fn update_rotation(
+        &mut self,
+        msg: RotateCmd,
+    ) -> Result<Vec<Option<RotationSubcommandDelta>>, ButtplugError> {
+        if self.last_rotation.len() < msg.rotations.len() {
+            self.last_rotation.resize(msg.rotations.len(), None);
+        }
+        let mut result = vec![None; self.last_rotation.len()];
+        for rotate_cmd in msg.rotations {
+            let index = rotate_cmd.index as usize;
+            let speed = rotate_cmd.speed;
+            let clockwise = rotate_cmd.clockwise;
+            let (speed_changed, clockwise_changed) = match self.last_rotation[index] {
+                Some((last_speed, last_clockwise)) => {
+                    (last_speed != speed, last_clockwise != clockwise)
+                }
+                // Nothing sent to this motor yet, so there's no previous
+                // command to diff against yet -- treat it as unchanged,
+                // matching the old behavior where the very first rotation
+                // command only primed the cache without writing anything.
+                None => (false, false),
+            };
+            result[index] = Some(RotationSubcommandDelta {
+                speed,
+                clockwise,
+                speed_changed,
+                clockwise_changed,
+            });
+            self.last_rotation[index] = Some((speed, clockwise));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::messages::{RotateSubcommand, VibrateSubcommand};
+
+    #[test]
+    fn test_update_vibration_reports_changed_on_first_command() {
+        let mut manager = GenericCommandManager::new();
+        let result = manager
+            .update_vibration(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]))
+            .unwrap();
+        let delta = result[0].expect("motor 0 should have a delta");
+        assert!(delta.speed_changed);
+    }
+
+    #[test]
+    fn test_update_vibration_reports_unchanged_when_speed_repeats() {
+        let mut manager = GenericCommandManager::new();
+        manager
+            .update_vibration(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]))
+            .unwrap();
+        let result = manager
+            .update_vibration(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]))
+            .unwrap();
+        let delta = result[0].expect("motor 0 should have a delta");
+        assert!(!delta.speed_changed);
+    }
+
+    #[test]
+    fn test_update_vibration_tracks_each_motor_independently() {
+        let mut manager = GenericCommandManager::new();
+        manager
+            .update_vibration(VibrateCmd::new(
+                0,
+                vec![VibrateSubcommand::new(0, 0.1), VibrateSubcommand::new(1, 0.9)],
+            ))
+            .unwrap();
+        // Only motor 1 changes this time; motor 0 shouldn't be reported as
+        // changed just because some other index in the same command was.
+        let result = manager
+            .update_vibration(VibrateCmd::new(
+                0,
+                vec![VibrateSubcommand::new(0, 0.1), VibrateSubcommand::new(1, 0.2)],
+            ))
+            .unwrap();
+        assert!(!result[0].unwrap().speed_changed);
+        assert!(result[1].unwrap().speed_changed);
+    }
+
+    #[test]
+    fn test_vibration_motor_count_reflects_widest_command_seen() {
+        let mut manager = GenericCommandManager::new();
+        assert_eq!(manager.vibration_motor_count(), 1);
+        manager
+            .update_vibration(VibrateCmd::new(
+                0,
+                vec![VibrateSubcommand::new(0, 0.1), VibrateSubcommand::new(1, 0.1), VibrateSubcommand::new(2, 0.1)],
+            ))
+            .unwrap();
+        assert_eq!(manager.vibration_motor_count(), 3);
+    }
+
+    #[test]
+    fn test_update_rotation_reports_unchanged_on_first_command() {
+        let mut manager = GenericCommandManager::new();
+        let result = manager
+            .update_rotation(RotateCmd::new(0, vec![RotateSubcommand::new(0, 0.5, true)]))
+            .unwrap();
+        let delta = result[0].expect("motor 0 should have a delta");
+        assert!(!delta.speed_changed);
+        assert!(!delta.clockwise_changed);
+    }
+
+    #[test]
+    fn test_update_rotation_tracks_each_motor_independently() {
+        let mut manager = GenericCommandManager::new();
+        manager
+            .update_rotation(RotateCmd::new(
+                0,
+                vec![RotateSubcommand::new(0, 0.5, true), RotateSubcommand::new(1, 0.5, true)],
+            ))
+            .unwrap();
+        // Only motor 1's direction changes; motor 0 should report no change.
+        let result = manager
+            .update_rotation(RotateCmd::new(
+                0,
+                vec![RotateSubcommand::new(0, 0.5, true), RotateSubcommand::new(1, 0.5, false)],
+            ))
+            .unwrap();
+        let motor_0 = result[0].unwrap();
+        assert!(!motor_0.speed_changed);
+        assert!(!motor_0.clockwise_changed);
+        let motor_1 = result[1].unwrap();
+        assert!(!motor_1.speed_changed);
+        assert!(motor_1.clockwise_changed);
+    }
+}
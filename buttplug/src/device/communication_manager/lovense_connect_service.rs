@@ -0,0 +1,387 @@
+// Lovense Connect is the companion phone app that bridges Lovense BLE toys to
+// a local HTTP server on the phone. Some users can't get a stable BLE stack
+// running (desktop BLE dongles are a perennial headache), but they can still
+// reach that HTTP server on the same LAN/USB-tether. This communication
+// manager polls it as an alternative transport to the BLE path that feeds
+// `LovenseProtocolCreator`, so the same `Lovense` protocol commands end up
+// going out over HTTP instead of GATT.
+use crate::device::{
+    communication_manager::{
+        DeviceCommunicationManager, DeviceCommunicationManagerCreator,
+    },
+    configuration_manager::DeviceProtocolConfiguration,
+    device::{
+        BoundedDeviceEventBroadcaster, ButtplugDeviceEvent, DeviceImpl, DeviceReadCmd,
+        DeviceSubscribeCmd, DeviceUnsubscribeCmd, DeviceWriteCmd, Endpoint,
+    },
+};
+use crate::core::errors::{ButtplugDeviceError, ButtplugError};
+use async_std::prelude::FutureExt;
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// How often we ask the phone app what toys it currently knows about. The app
+// only serves HTTP while it's in the foreground, so a failed poll is skipped
+// and retried after this interval; toys already found are not dropped.
+const SCAN_POLL_INTERVAL_MS: u64 = 1000;
+// How long a single GetToys/Command request is allowed to take before we
+// assume the phone app has gone away (screen locked, app backgrounded, etc).
+const REQUEST_TIMEOUT_MS: u64 = 1000;
+
+#[derive(Deserialize, Debug, Clone)]
+struct LovenseConnectToy {
+    id: String,
+    name: String,
+    status: String,
+    battery: i32,
+}
+
+pub struct LovenseConnectServiceCommunicationManagerCreator {
+    host: String,
+    port: u16,
+    token: String,
+}
+
+impl LovenseConnectServiceCommunicationManagerCreator {
+    pub fn new(host: &str, port: u16, token: &str) -> Self {
+        Self {
+            host: host.to_owned(),
+            port,
+            token: token.to_owned(),
+        }
+    }
+}
+
+impl DeviceCommunicationManagerCreator for LovenseConnectServiceCommunicationManagerCreator {
+    fn create(&self, event_sender: async_std::sync::Sender<ButtplugDeviceEvent>) -> Box<dyn DeviceCommunicationManager> {
+        Box::new(LovenseConnectServiceCommunicationManager::new(
+            &self.host,
+            self.port,
+            &self.token,
+            event_sender,
+        ))
+    }
+}
+
+// Polls `http://<host>:<port>/GetToys` on the Lovense Connect app and surfaces
+// each toy it finds as a `LovenseConnectServiceDeviceImpl`. The scan loop is a
+// timed retry rather than a one-shot, since the HTTP service appears and
+// disappears as the phone app starts and stops.
+pub struct LovenseConnectServiceCommunicationManager {
+    host: String,
+    port: u16,
+    token: String,
+    event_sender: async_std::sync::Sender<ButtplugDeviceEvent>,
+    // An `AtomicBool` rather than the `Arc<Mutex<_>>` the rest of this
+    // struct uses, since `is_scanning()` is a sync fn on the shared
+    // `DeviceCommunicationManager` trait and has no way to await a lock.
+    scanning: Arc<AtomicBool>,
+    known_toys: Arc<Mutex<HashMap<String, LovenseConnectToy>>>,
+}
+
+impl LovenseConnectServiceCommunicationManager {
+    fn new(
+        host: &str,
+        port: u16,
+        token: &str,
+        event_sender: async_std::sync::Sender<ButtplugDeviceEvent>,
+    ) -> Self {
+        Self {
+            host: host.to_owned(),
+            port,
+            token: token.to_owned(),
+            event_sender,
+            scanning: Arc::new(AtomicBool::new(false)),
+            known_toys: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn base_url(&self) -> String {
+        format!("http://{}:{}", self.host, self.port)
+    }
+
+    async fn get_toys(&self) -> Result<Vec<LovenseConnectToy>, ButtplugError> {
+        let url = format!("{}/GetToys", self.base_url());
+        let resp = surf::get(&url)
+            .recv_string()
+            .timeout(Duration::from_millis(REQUEST_TIMEOUT_MS))
+            .await
+            .map_err(|_| ButtplugDeviceError::new("Lovense Connect service did not respond in time."))?
+            .map_err(|e| ButtplugDeviceError::new(&format!("Lovense Connect service request failed: {}", e)))?;
+        // The service returns a JSON object keyed by toy id, not an array, so
+        // we decode into a map and then pull out the values.
+        let toys: HashMap<String, LovenseConnectToy> = serde_json::from_str(&resp)
+            .map_err(|e| ButtplugDeviceError::new(&format!("Lovense Connect service returned unparseable JSON: {}", e)))?;
+        Ok(toys.into_values().collect())
+    }
+}
+
+#[async_trait]
+impl DeviceCommunicationManager for LovenseConnectServiceCommunicationManager {
+    async fn start_scanning(&self) {
+        self.scanning.store(true, Ordering::SeqCst);
+        let scanning = self.scanning.clone();
+        let known_toys = self.known_toys.clone();
+        let host = self.host.clone();
+        let port = self.port;
+        let token = self.token.clone();
+        let event_sender = self.event_sender.clone();
+        task::spawn(async move {
+            let manager = LovenseConnectServiceCommunicationManager::new(
+                &host, port, &token, event_sender.clone(),
+            );
+            while scanning.load(Ordering::SeqCst) {
+                if let Ok(toys) = manager.get_toys().await {
+                    let mut seen = known_toys.lock().await;
+                    for toy in toys {
+                        if toy.status != "on" && toy.status != "1" {
+                            continue;
+                        }
+                        if !seen.contains_key(&toy.id) {
+                            let device_impl = LovenseConnectServiceDeviceImpl::new(
+                                &host, port, &token, &toy.id, &toy.name,
+                            );
+                            let _ = event_sender
+                                .send(ButtplugDeviceEvent::DeviceFound(Box::new(device_impl)))
+                                .await;
+                        }
+                        seen.insert(toy.id.clone(), toy);
+                    }
+                }
+                task::sleep(Duration::from_millis(SCAN_POLL_INTERVAL_MS)).await;
+            }
+        });
+    }
+
+    async fn stop_scanning(&self) {
+        self.scanning.store(false, Ordering::SeqCst);
+    }
+
+    fn is_scanning(&self) -> bool {
+        self.scanning.load(Ordering::SeqCst)
+    }
+}
+
+// Translates the existing Lovense text commands into HTTP GETs of the form
+// `/<token>/Command?command=<cmd>&toy=<id>`, so everything above the
+// transport (the `Lovense` protocol itself) is none the wiser that it isn't
+// talking BLE.
+pub struct LovenseConnectServiceDeviceImpl {
+    host: String,
+    port: u16,
+    token: String,
+    toy_id: String,
+    name: String,
+    subscribed: Arc<Mutex<bool>>,
+    event_sender: async_std::sync::Sender<ButtplugDeviceEvent>,
+    event_receiver: async_std::sync::Receiver<ButtplugDeviceEvent>,
+}
+
+impl LovenseConnectServiceDeviceImpl {
+    fn new(host: &str, port: u16, token: &str, toy_id: &str, name: &str) -> Self {
+        let (event_sender, event_receiver) = async_std::sync::channel(256);
+        Self {
+            host: host.to_owned(),
+            port,
+            token: token.to_owned(),
+            toy_id: toy_id.to_owned(),
+            name: name.to_owned(),
+            subscribed: Arc::new(Mutex::new(false)),
+            event_sender,
+            event_receiver,
+        }
+    }
+
+    fn command_url(&self, command: &str) -> String {
+        format!(
+            "http://{}:{}/{}/Command?command={}&toy={}",
+            self.host, self.port, self.token, command, self.toy_id
+        )
+    }
+
+    // The BLE side speaks e.g. "Vibrate:10;" or "Rotate1:5;" with a trailing
+    // semicolon and (sometimes) a motor index baked into the verb; the HTTP
+    // side wants the same verb and argument without the semicolon.
+    fn ble_command_to_http_command(ble_command: &str) -> String {
+        ble_command.trim_end_matches(';').to_owned()
+    }
+
+    // Shared by `read_value` and the `"Battery;"` write special-case below:
+    // there's no dedicated battery endpoint over this transport, the level
+    // just comes back as a field on the same toy entry `/GetToys` already
+    // returns.
+    async fn fetch_battery(&self) -> Result<i32, ButtplugError> {
+        let url = format!("http://{}:{}/GetToys", self.host, self.port);
+        let resp = surf::get(&url)
+            .recv_string()
+            .timeout(Duration::from_millis(REQUEST_TIMEOUT_MS))
+            .await
+            .map_err(|_| ButtplugDeviceError::new("Lovense Connect service did not respond in time."))?
+            .map_err(|e| ButtplugDeviceError::new(&format!("Lovense Connect service request failed: {}", e)))?;
+        let toys: HashMap<String, LovenseConnectToy> = serde_json::from_str(&resp)
+            .map_err(|e| ButtplugDeviceError::new(&format!("Lovense Connect service returned unparseable JSON: {}", e)))?;
+        let toy = toys
+            .get(&self.toy_id)
+            .ok_or_else(|| ButtplugDeviceError::new("Toy disappeared from Lovense Connect service."))?;
+        Ok(toy.battery)
+    }
+}
+
+#[async_trait]
+impl DeviceImpl for LovenseConnectServiceDeviceImpl {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write_value(&self, msg: DeviceWriteCmd) -> Result<(), ButtplugError> {
+        if msg.endpoint != Endpoint::Tx {
+            return Err(ButtplugDeviceError::new(
+                "Lovense Connect service devices only accept writes on Endpoint::Tx.",
+            )
+            .into());
+        }
+        let ble_command = std::str::from_utf8(&msg.data)
+            .map_err(|_| ButtplugDeviceError::new("Lovense command was not valid UTF-8."))?;
+        let command = Self::ble_command_to_http_command(ble_command);
+        // There's no "DeviceType" command on the Lovense Connect HTTP API
+        // either -- `LovenseProtocolCreator::try_create_protocol` writes
+        // "DeviceType;" and waits for a notification to identify the toy,
+        // but we already know what toy this is from the `/GetToys` poll
+        // that found it (that's where `self.name` came from), so we can
+        // answer locally instead of waiting on a reply nothing would ever
+        // send over this transport.
+        if command == "DeviceType" {
+            if *self.subscribed.lock().await {
+                self
+                    .event_sender
+                    .send(ButtplugDeviceEvent::Notification(
+                        Endpoint::Rx,
+                        self.name.clone().into_bytes(),
+                    ))
+                    .await;
+            }
+            return Ok(());
+        }
+        // There's no "Battery" command on the Lovense Connect HTTP API --
+        // battery level only ever comes back as a field on `/GetToys`. The
+        // `Lovense` protocol's `BatteryLevelCmd` handler subscribes, writes
+        // "Battery;", and waits for a notification, so we fetch the level
+        // here and synthesize that notification ourselves rather than
+        // hitting `/Command` with a command the service doesn't understand.
+        if command == "Battery" {
+            let battery = self.fetch_battery().await?;
+            if *self.subscribed.lock().await {
+                self
+                    .event_sender
+                    .send(ButtplugDeviceEvent::Notification(
+                        Endpoint::Rx,
+                        battery.to_string().into_bytes(),
+                    ))
+                    .await;
+            }
+            return Ok(());
+        }
+        let url = self.command_url(&command);
+        surf::get(&url)
+            .recv_string()
+            .timeout(Duration::from_millis(REQUEST_TIMEOUT_MS))
+            .await
+            .map_err(|_| ButtplugDeviceError::new("Lovense Connect service did not respond in time."))?
+            .map_err(|e| ButtplugDeviceError::new(&format!("Lovense Connect service request failed: {}", e)))?;
+        Ok(())
+    }
+
+    // Battery doesn't have its own endpoint over HTTP, it just comes back as
+    // part of GetToys, so we poll that same endpoint and pull the one toy's
+    // battery back out rather than issuing a device-specific read.
+    async fn read_value(&self, _msg: DeviceReadCmd) -> Result<Vec<u8>, ButtplugError> {
+        Ok(self.fetch_battery().await?.to_string().into_bytes())
+    }
+
+    async fn subscribe(&self, _msg: DeviceSubscribeCmd) -> Result<(), ButtplugError> {
+        *self.subscribed.lock().await = true;
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, _msg: DeviceUnsubscribeCmd) -> Result<(), ButtplugError> {
+        *self.subscribed.lock().await = false;
+        Ok(())
+    }
+
+    fn get_event_receiver(&self) -> BoundedDeviceEventBroadcaster {
+        self.event_receiver.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::prelude::StreamExt;
+
+    #[test]
+    fn test_device_type_write_synthesizes_notification_without_http() {
+        let device = LovenseConnectServiceDeviceImpl::new(
+            "127.0.0.1", 30010, "token", "toy-1", "Max",
+        );
+        async_std::task::block_on(async {
+            device
+                .subscribe(DeviceSubscribeCmd::new(Endpoint::Rx).into())
+                .await
+                .unwrap();
+            let mut receiver = device.get_event_receiver();
+            device
+                .write_value(DeviceWriteCmd::new(
+                    Endpoint::Tx,
+                    "DeviceType;".as_bytes().to_vec(),
+                    false,
+                ))
+                .await
+                .unwrap();
+            match receiver.next().await {
+                Some(ButtplugDeviceEvent::Notification(endpoint, data)) => {
+                    assert_eq!(endpoint, Endpoint::Rx);
+                    assert_eq!(data, b"Max".to_vec());
+                }
+                _ => panic!("Expected a synthesized DeviceType notification"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_is_scanning_reflects_start_and_stop_scanning() {
+        let (event_sender, _event_receiver) = async_std::sync::channel(256);
+        let manager = LovenseConnectServiceCommunicationManager::new(
+            "127.0.0.1", 30010, "token", event_sender,
+        );
+        async_std::task::block_on(async {
+            assert!(!manager.is_scanning());
+            manager.start_scanning().await;
+            assert!(manager.is_scanning());
+            manager.stop_scanning().await;
+            assert!(!manager.is_scanning());
+        });
+    }
+
+    #[test]
+    fn test_device_type_write_is_silent_when_not_subscribed() {
+        let device = LovenseConnectServiceDeviceImpl::new(
+            "127.0.0.1", 30010, "token", "toy-1", "Max",
+        );
+        async_std::task::block_on(async {
+            device
+                .write_value(DeviceWriteCmd::new(
+                    Endpoint::Tx,
+                    "DeviceType;".as_bytes().to_vec(),
+                    false,
+                ))
+                .await
+                .unwrap();
+            assert!(device.get_event_receiver().is_empty());
+        });
+    }
+}
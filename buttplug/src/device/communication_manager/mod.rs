@@ -0,0 +1 @@
+pub mod lovense_connect_service;
@@ -0,0 +1,144 @@
+use super::fleshlight_launch_helper::get_speed;
+use super::{ButtplugProtocol, ButtplugProtocolCreator};
+use crate::{create_buttplug_protocol, device::configuration_manager::DeviceProtocolConfiguration};
+use async_trait::async_trait;
+
+// Fleshlight Launch (and Kiiroo devices that speak the same protocol) moves
+// to an absolute position over a duration rather than taking a speed
+// directly, so `LinearCmd` has to be turned into a firmware speed byte via
+// `get_speed` before it can be sent. We keep the last commanded position
+// around so each new move can be expressed as a delta from where the toy
+// actually is.
+
+pub struct FleshlightLaunchProtocolCreator {
+    config: DeviceProtocolConfiguration,
+}
+
+impl FleshlightLaunchProtocolCreator {
+    pub fn new(config: DeviceProtocolConfiguration) -> Self {
+        Self { config }
+    }
+}
+
+// Unlike Lovense, Fleshlight Launch (and the Kiiroo devices sharing its
+// protocol) identify themselves via BLE advertised name/services rather than
+// a text handshake, so there's no round trip needed before we can resolve
+// device attributes from the identifier.
+#[async_trait]
+impl ButtplugProtocolCreator for FleshlightLaunchProtocolCreator {
+    async fn try_create_protocol(
+        &self,
+        _device_impl: &Box<dyn DeviceImpl>,
+    ) -> Result<Box<dyn ButtplugProtocol>, ButtplugError> {
+        let (names, attrs) = self.config.get_attributes("FleshlightLaunch").unwrap();
+        let name = names.get("en-us").unwrap();
+        Ok(Box::new(FleshlightLaunch::new(name, attrs)))
+    }
+}
+
+create_buttplug_protocol!(
+    FleshlightLaunch,
+    false,
+    (
+        (previous_position: Arc<Mutex<f64>> = Arc::new(Mutex::new(0.0f64)))
+    ),
+    ((LinearCmd, {
+        // Like the Lovense VibrateCmd/RotateCmd handlers, don't assume the
+        // command vector is non-empty -- a LinearCmd with no vectors is a
+        // no-op, not a panic.
+        if let Some(v) = msg.vectors.get(0) {
+            let previous_position = {
+                let mut previous_position = self.previous_position.lock().await;
+                let previous = *previous_position;
+                *previous_position = v.position.min(1.0f64).max(0.0f64);
+                previous
+            };
+            let distance = (v.position.min(1.0f64).max(0.0f64) - previous_position).abs();
+            // Nothing to do if we're already there, and nothing to divide by
+            // zero over if get_speed() gets handed a zero-distance move.
+            if distance != 0f64 {
+                let speed = get_speed(distance, v.duration).min(1.0f64).max(0.0f64);
+                let position_byte = (v.position.min(1.0f64).max(0.0f64) * 99f64) as u8;
+                let speed_byte = (speed * 99f64) as u8;
+                let fleshlight_cmd = vec![position_byte, speed_byte];
+                device.write_value(DeviceWriteCmd::new(Endpoint::Tx, fleshlight_cmd, false)).await?;
+            }
+        }
+        Ok(ButtplugMessageUnion::Ok(messages::Ok::default()))
+    }))
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::TestDeviceImpl;
+
+    async fn create_protocol(device_impl: &Box<dyn DeviceImpl>) -> Box<dyn ButtplugProtocol> {
+        let config = DeviceProtocolConfiguration::default();
+        let creator = FleshlightLaunchProtocolCreator::new(config);
+        creator.try_create_protocol(device_impl).await.unwrap()
+    }
+
+    #[test]
+    fn test_linear_cmd_moves_and_derives_position_speed_bytes() {
+        let device = TestDeviceImpl::new("Test Launch");
+        async_std::task::block_on(async {
+            let device_impl: Box<dyn DeviceImpl> = Box::new(device.clone());
+            let protocol = create_protocol(&device_impl).await;
+            protocol
+                .handle_command(&device_impl, LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.5)]).into())
+                .await
+                .unwrap();
+            let speed = get_speed(0.5, 500).min(1.0f64).max(0.0f64);
+            let expected = vec![(0.5f64 * 99f64) as u8, (speed * 99f64) as u8];
+            let written = device.written_commands().await;
+            assert_eq!(written.last().unwrap().data, expected);
+        });
+    }
+
+    #[test]
+    fn test_linear_cmd_zero_distance_move_is_noop() {
+        let device = TestDeviceImpl::new("Test Launch");
+        async_std::task::block_on(async {
+            let device_impl: Box<dyn DeviceImpl> = Box::new(device.clone());
+            let protocol = create_protocol(&device_impl).await;
+            // Device starts at position 0.0, so commanding 0.0 again is a
+            // zero-distance move.
+            protocol
+                .handle_command(&device_impl, LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.0)]).into())
+                .await
+                .unwrap();
+            assert!(device.written_commands().await.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_linear_cmd_empty_vectors_is_noop() {
+        let device = TestDeviceImpl::new("Test Launch");
+        async_std::task::block_on(async {
+            let device_impl: Box<dyn DeviceImpl> = Box::new(device.clone());
+            let protocol = create_protocol(&device_impl).await;
+            protocol
+                .handle_command(&device_impl, LinearCmd::new(0, vec![]).into())
+                .await
+                .unwrap();
+            assert!(device.written_commands().await.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_linear_cmd_clamps_out_of_range_position() {
+        let device = TestDeviceImpl::new("Test Launch");
+        async_std::task::block_on(async {
+            let device_impl: Box<dyn DeviceImpl> = Box::new(device.clone());
+            let protocol = create_protocol(&device_impl).await;
+            protocol
+                .handle_command(&device_impl, LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 1.5)]).into())
+                .await
+                .unwrap();
+            let written = device.written_commands().await;
+            // 1.5 clamps down to 1.0, which maps to the max position byte (99).
+            assert_eq!(written.last().unwrap().data[0], 99u8);
+        });
+    }
+}
@@ -0,0 +1,121 @@
+// Test-only `DeviceImpl`/communication manager pair used to exercise protocol
+// implementations (handshakes, command translation) without real hardware or
+// a real Bluetooth stack underneath them.
+use crate::core::errors::ButtplugError;
+use crate::device::device::{
+    BoundedDeviceEventBroadcaster, ButtplugDeviceEvent, DeviceImpl, DeviceReadCmd,
+    DeviceSubscribeCmd, DeviceUnsubscribeCmd, DeviceWriteCmd,
+};
+use async_std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use std::collections::VecDeque;
+
+#[derive(Default)]
+struct TestDeviceImplState {
+    // Writes the test device has seen, for assertions against what a
+    // protocol's command handler actually sent.
+    written: Vec<DeviceWriteCmd>,
+    // Notification payloads queued up ahead of time via
+    // `TestDeviceImpl::add_notification`. The oldest one is emitted on the
+    // event broadcaster (tagged with the written endpoint) on the next write
+    // of any endpoint while a `subscribe` is in place.
+    queued_notifications: VecDeque<Vec<u8>>,
+    subscribed: bool,
+    removed: bool,
+}
+
+// A `DeviceImpl` double that lets tests pre-load notification payloads so
+// protocol initializers and command handlers that read back from the device
+// (e.g. Lovense's `"DeviceType;"` handshake) can be exercised end-to-end.
+// Cheaply `Clone`able (it's just a name plus a shared, mutex-guarded state
+// handle, and a shared event channel) so a test can keep one handle for
+// assertions while handing another to a protocol as its `Box<dyn
+// DeviceImpl>`.
+//
+// The event channel is created once, in `new()`, and lives for the life of
+// the device -- every caller's `get_event_receiver()` just clones the one
+// live receiver. This matters because real call sites (e.g.
+// `LovenseProtocolCreator::try_create_protocol`, the `BatteryLevelCmd`
+// handler) always `subscribe()` then `write_value()` *before* they call
+// `get_event_receiver().next().await`; if the channel were minted fresh on
+// each `get_event_receiver()` call, any notification emitted by an earlier
+// `write_value()` would already have been sent into a channel nothing was
+// listening to yet, and the later receiver would wait on it forever.
+#[derive(Clone)]
+pub struct TestDeviceImpl {
+    name: String,
+    state: Arc<Mutex<TestDeviceImplState>>,
+    event_sender: async_std::sync::Sender<ButtplugDeviceEvent>,
+    event_receiver: async_std::sync::Receiver<ButtplugDeviceEvent>,
+}
+
+impl TestDeviceImpl {
+    pub fn new(name: &str) -> Self {
+        let (event_sender, event_receiver) = async_std::sync::channel(256);
+        Self {
+            name: name.to_owned(),
+            state: Arc::new(Mutex::new(TestDeviceImplState::default())),
+            event_sender,
+            event_receiver,
+        }
+    }
+
+    // Queues a notification payload to be emitted the next time a write
+    // happens after a subscription is in place. Tests call this before
+    // triggering the write that should provoke the response (e.g. before
+    // calling `try_create_protocol`, so the `"DeviceType;"` write gets a
+    // `"W:..."` answer).
+    pub async fn add_notification(&self, data: Vec<u8>) {
+        self.state.lock().await.queued_notifications.push_back(data);
+    }
+
+    // Simulates the device disconnecting mid-handshake/command, so error
+    // branches that key off `ButtplugDeviceEvent::Removed` can be tested.
+    pub async fn remove(&self) {
+        self.state.lock().await.removed = true;
+        self.event_sender.send(ButtplugDeviceEvent::Removed).await;
+    }
+
+    pub async fn written_commands(&self) -> Vec<DeviceWriteCmd> {
+        self.state.lock().await.written.clone()
+    }
+}
+
+#[async_trait]
+impl DeviceImpl for TestDeviceImpl {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write_value(&self, msg: DeviceWriteCmd) -> Result<(), ButtplugError> {
+        let mut state = self.state.lock().await;
+        let endpoint = msg.endpoint;
+        state.written.push(msg);
+        if state.subscribed && !state.removed {
+            if let Some(data) = state.queued_notifications.pop_front() {
+                self.event_sender
+                    .send(ButtplugDeviceEvent::Notification(endpoint, data))
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_value(&self, _msg: DeviceReadCmd) -> Result<Vec<u8>, ButtplugError> {
+        Ok(vec![])
+    }
+
+    async fn subscribe(&self, _msg: DeviceSubscribeCmd) -> Result<(), ButtplugError> {
+        self.state.lock().await.subscribed = true;
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, _msg: DeviceUnsubscribeCmd) -> Result<(), ButtplugError> {
+        self.state.lock().await.subscribed = false;
+        Ok(())
+    }
+
+    fn get_event_receiver(&self) -> BoundedDeviceEventBroadcaster {
+        self.event_receiver.clone()
+    }
+}